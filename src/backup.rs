@@ -0,0 +1,57 @@
+use crate::Result;
+pub use rusqlite::backup::Progress;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::Connection as SqliteConnection;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PAGES_PER_STEP: i32 = 100;
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+static BACKUP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Copies `src` into a new timestamped `.bak` file inside `dir`, using
+/// [`rusqlite::backup::Backup`] so the copy can proceed while `src` is in use. Returns the path
+/// of the file written, so a failed or data-destructive migration can be restored from it.
+///
+/// Drives the backup one step at a time (rather than
+/// [`Backup::run_to_completion`](rusqlite::backup::Backup::run_to_completion), which only accepts
+/// a bare `fn(Progress)`) so `progress`, if given, may be a capturing closure.
+pub fn backup_to_dir(
+    src: &SqliteConnection,
+    dir: &Path,
+    mut progress: Option<&mut dyn FnMut(Progress)>,
+) -> Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or(0);
+
+    // A millisecond timestamp alone can collide when several backups run in quick succession
+    // (e.g. one per migration in a multi-migration run), which would silently overwrite an
+    // earlier restore point. A per-process counter keeps every path distinct.
+    let sequence = BACKUP_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    let path = dir.join(format!("schemamama-{}-{}.bak", timestamp, sequence));
+
+    let mut dst = SqliteConnection::open(&path)?;
+    let mut backup = Backup::new(src, &mut dst)?;
+
+    loop {
+        let step_result = backup.step(PAGES_PER_STEP)?;
+
+        if let Some(callback) = progress.as_deref_mut() {
+            callback(backup.progress());
+        }
+
+        match step_result {
+            StepResult::Done => break,
+            StepResult::More => {}
+            StepResult::Busy | StepResult::Locked => sleep(RETRY_DELAY),
+        }
+    }
+
+    Ok(path)
+}
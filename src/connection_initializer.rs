@@ -0,0 +1,33 @@
+use rusqlite::{Connection as SqliteConnection, Result as SqliteResult};
+
+/// Runs setup/teardown for a connection outside of any migration transaction.
+///
+/// Some `PRAGMA`s that migrations commonly want &mdash; `journal_mode = wal`,
+/// `foreign_keys = ON` &mdash; cannot be toggled while inside a transaction, yet
+/// [`SqliteAdapter`](crate::SqliteAdapter) runs every migration in one. A `ConnectionInitializer`
+/// gives callers a place to run that setup: `prepare` runs once when the adapter is
+/// constructed, and `finish` runs once whenever the caller invokes
+/// [`SqliteAdapter::finish`](crate::SqliteAdapter::finish).
+///
+/// `schemamama::Migrator` takes ownership of the adapter and exposes no way to get it back, so
+/// `finish` can't be called after `Migrator::up`/`down` return. Callers who need `finish` should
+/// drive migrations directly through [`SqliteAdapter`](crate::SqliteAdapter)'s `Adapter` impl
+/// instead of handing the adapter to a `Migrator`.
+pub trait ConnectionInitializer {
+    /// Called once, outside any transaction, when the adapter is constructed.
+    #[allow(unused_variables)]
+    fn prepare(&self, conn: &SqliteConnection) -> SqliteResult<()> {
+        Ok(())
+    }
+
+    /// Called outside any transaction each time [`SqliteAdapter::finish`](crate::SqliteAdapter::finish)
+    /// is invoked; it is not called automatically when a migration run completes.
+    #[allow(unused_variables)]
+    fn finish(&self, conn: &SqliteConnection) -> SqliteResult<()> {
+        Ok(())
+    }
+}
+
+pub(crate) struct NoopConnectionInitializer;
+
+impl ConnectionInitializer for NoopConnectionInitializer {}
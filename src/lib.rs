@@ -2,17 +2,23 @@
     html_root_url = "https://cmsd2.github.io/rust-docs/schemamama_rusqlite/schemamama_rusqlite/"
 )]
 
-#[allow(unused_imports)]
-use log::warn;
-use rusqlite::{
-    Connection as SqliteConnection, Error as SqliteError, Result as SqliteResult, Row as SqliteRow,
-};
+use rusqlite::{Connection as SqliteConnection, Error as SqliteError, Result as SqliteResult};
 use schemamama::{Adapter, Migration, Version};
 use std::cell::RefCell;
 use std::collections::BTreeSet;
+use std::path::PathBuf;
 use std::rc::Rc;
 use thiserror::Error;
 
+pub mod backup;
+mod connection_initializer;
+mod sql_migration;
+mod version_store;
+pub use connection_initializer::ConnectionInitializer;
+use connection_initializer::NoopConnectionInitializer;
+pub use sql_migration::SqlMigration;
+pub use version_store::{TableVersionStore, UserVersionStore, VersionStore};
+
 #[derive(Error, Debug)]
 pub enum SqliteMigrationError {
     #[error("unknown error")]
@@ -21,6 +27,24 @@ pub enum SqliteMigrationError {
     RusqliteError(#[from] SqliteError),
     #[error("sql error")]
     SqlError(String),
+    #[error("migration checksum validation failed for versions: {0:?}")]
+    ValidationError(Vec<Version>),
+    #[error("migration failed after taking a backup at {}: {source}", path.display())]
+    FailedAfterBackup {
+        path: PathBuf,
+        #[source]
+        source: Box<SqliteMigrationError>,
+    },
+    #[error("migration versions {0} and {1} are not contiguous")]
+    NonContiguousVersions(Version, Version),
+    #[error(
+        "migration version {0} does not fit in PRAGMA user_version, which is a 32-bit integer"
+    )]
+    VersionOutOfRange(Version),
+    #[error("schema table not initialized; call SqliteAdapter::setup_schema first")]
+    SchemaNotInitialized,
+    #[error("connection is read-only")]
+    ReadOnlyConnection,
 }
 
 pub type Result<T> = std::result::Result<T, SqliteMigrationError>;
@@ -40,62 +64,132 @@ pub trait SqliteMigration: Migration {
     fn down(&self, conn: &SqliteConnection) -> SqliteResult<()> {
         Ok(())
     }
+
+    /// A checksum of this migration's SQL/definition, recorded alongside its version when it is
+    /// applied and later rechecked by [`SqliteAdapter::validate`] to detect drift. Migrations
+    /// that don't carry their own SQL text have nothing meaningful to hash, so this returns
+    /// `None` by default.
+    fn checksum(&self) -> Option<String> {
+        None
+    }
 }
 
 /// An adapter that allows its migrations to act upon PostgreSQL connection transactions.
 pub struct SqliteAdapter {
     connection: Rc<RefCell<SqliteConnection>>,
+    backup_dir: Option<PathBuf>,
+    backup_progress: Option<Box<dyn Fn(backup::Progress)>>,
+    initializer: Box<dyn ConnectionInitializer>,
+    version_store: Box<dyn VersionStore>,
 }
 
 impl SqliteAdapter {
-    /// Create a new migrator tied to a SQLite connection.
-    pub fn new(connection: Rc<RefCell<SqliteConnection>>) -> SqliteAdapter {
-        SqliteAdapter {
-            connection: connection,
+    /// Create a new migrator tied to a SQLite connection, tracking applied versions in a
+    /// [`TableVersionStore`] unless `version_store` says otherwise.
+    ///
+    /// `initializer`, if given, has its [`ConnectionInitializer::prepare`] called once, outside
+    /// any transaction, before this function returns.
+    ///
+    /// Returns [`SqliteMigrationError::ReadOnlyConnection`] up front if `connection` can't be
+    /// written to, rather than letting the first migration fail confusingly.
+    pub fn new(
+        connection: Rc<RefCell<SqliteConnection>>,
+        initializer: Option<Box<dyn ConnectionInitializer>>,
+        version_store: Option<Box<dyn VersionStore>>,
+    ) -> Result<SqliteAdapter> {
+        let initializer = initializer.unwrap_or_else(|| Box::new(NoopConnectionInitializer));
+        let version_store = version_store.unwrap_or_else(|| Box::new(TableVersionStore));
+
+        let conn = connection.borrow();
+
+        if conn.is_readonly(rusqlite::DatabaseName::Main)? {
+            return Err(SqliteMigrationError::ReadOnlyConnection);
         }
-    }
 
-    /// Create the tables Schemamama requires to keep track of schema state. If the tables already
-    /// exist, this function has no operation.
-    pub fn setup_schema(&self) {
-        let conn = self.connection.borrow();
+        initializer.prepare(&conn)?;
+        drop(conn);
 
-        let query = "CREATE TABLE IF NOT EXISTS schemamama (version BIGINT PRIMARY KEY);";
-        if let Err(e) = conn.execute(query, []) {
-            panic!("Schema setup failed: {:?}", e);
-        }
+        Ok(SqliteAdapter {
+            connection,
+            backup_dir: None,
+            backup_progress: None,
+            initializer,
+            version_store,
+        })
     }
 
-    // Panics if `setup_schema` hasn't previously been called or if the insertion query otherwise
-    // fails.
-    fn record_version(&self, conn: &SqliteConnection, version: Version) -> SqliteResult<()> {
-        let query = "INSERT INTO schemamama (version) VALUES ($1);";
-        let mut stmt = conn.prepare(query)?;
+    /// Create a migrator that takes a full online backup of the database into `backup_dir`
+    /// before every `apply_migration`/`revert_migration`, so a failed or destructive migration
+    /// can be restored from a snapshot.
+    pub fn with_backup(
+        connection: Rc<RefCell<SqliteConnection>>,
+        initializer: Option<Box<dyn ConnectionInitializer>>,
+        version_store: Option<Box<dyn VersionStore>>,
+        backup_dir: PathBuf,
+    ) -> Result<SqliteAdapter> {
+        let mut adapter = Self::new(connection, initializer, version_store)?;
+        adapter.backup_dir = Some(backup_dir);
+        Ok(adapter)
+    }
 
-        match stmt.execute(&[&version]) {
-            Err(e) => {
-                warn!("Failed to delete version {:?}: {:?}", version, e);
-                Err(e)
-            }
-            _ => Ok(()),
-        }
+    /// Run this adapter's [`ConnectionInitializer::finish`] hook, outside any transaction. Call
+    /// this once after a migration run.
+    ///
+    /// `schemamama::Migrator` takes ownership of its adapter and never hands it back, so this
+    /// is only reachable by driving migrations directly through this adapter's `Adapter` impl
+    /// (`apply_migration`/`revert_migration`) rather than through a `Migrator`.
+    pub fn finish(&self) -> SqliteResult<()> {
+        self.initializer.finish(&self.connection.borrow())
+    }
+
+    /// Register a callback invoked periodically with backup progress while a pre-migration
+    /// backup (enabled via [`SqliteAdapter::with_backup`]) is running.
+    pub fn set_backup_progress_callback<F: Fn(backup::Progress) + 'static>(&mut self, callback: F) {
+        self.backup_progress = Some(Box::new(callback));
     }
 
-    // Panics if `setup_schema` hasn't previously been called or if the deletion query otherwise
-    // fails.
-    fn erase_version(&self, conn: &SqliteConnection, version: Version) -> SqliteResult<()> {
-        let query = "DELETE FROM schemamama WHERE version = $1;";
-        let mut stmt = conn.prepare(query).unwrap();
+    fn maybe_backup(&self) -> Result<Option<PathBuf>> {
+        let backup_dir = match &self.backup_dir {
+            Some(backup_dir) => backup_dir,
+            None => return Ok(None),
+        };
+
+        let conn = self.connection.borrow();
 
-        match stmt.execute(&[&version]) {
-            Err(e) => {
-                warn!("Failed to delete version {:?}: {:?}", version, e);
-                Err(e)
+        let path = match &self.backup_progress {
+            Some(callback) => {
+                let mut relay = |progress: backup::Progress| callback(progress);
+                backup::backup_to_dir(&conn, backup_dir, Some(&mut relay))?
             }
-            _ => Ok(()),
+            None => backup::backup_to_dir(&conn, backup_dir, None)?,
+        };
+
+        Ok(Some(path))
+    }
+
+    fn attach_backup_path(
+        &self,
+        error: SqliteError,
+        backup_path: &Option<PathBuf>,
+    ) -> SqliteMigrationError {
+        let error = version_store::map_missing_table(error);
+
+        match backup_path {
+            Some(path) => SqliteMigrationError::FailedAfterBackup {
+                path: path.clone(),
+                source: Box::new(error),
+            },
+            None => error,
         }
     }
 
+    /// Create whatever this adapter's [`VersionStore`] needs to keep track of schema state. If
+    /// it already exists and is up to date, this function has no operation; a `schemamama`
+    /// table that lacks the `checksum` column has it added.
+    pub fn setup_schema(&self) -> Result<()> {
+        self.version_store.setup(&self.connection.borrow())
+    }
+
     fn execute_transaction<F>(&self, block: F) -> SqliteResult<()>
     where
         F: Fn(&SqliteConnection) -> SqliteResult<()>,
@@ -109,28 +203,39 @@ impl SqliteAdapter {
         tx.commit()
     }
 
-    fn query_row<T, F>(&self, q: &str, block: F) -> SqliteResult<T>
-    where
-        F: FnOnce(&SqliteRow) -> SqliteResult<T>,
-    {
+    /// Recompute the checksum of each of `migrations` and compare it against what was recorded
+    /// when that version was applied, to detect a previously-applied migration whose body has
+    /// since changed. Migrations that return `None` from [`SqliteMigration::checksum`], or that
+    /// haven't been applied yet, are skipped.
+    ///
+    /// Returns [`SqliteMigrationError::ValidationError`] listing every version whose stored
+    /// checksum no longer matches.
+    pub fn validate(&self, migrations: &[&dyn SqliteMigration]) -> Result<()> {
         let conn = self.connection.borrow();
 
-        let result = conn.query_row(q, [], block)?;
+        let mut drifted = Vec::new();
 
-        Ok(result)
-    }
-
-    fn query_map<T, F>(&self, q: &str, block: F) -> SqliteResult<Vec<T>>
-    where
-        F: FnMut(&SqliteRow) -> SqliteResult<T>,
-    {
-        let conn = self.connection.borrow();
+        for migration in migrations {
+            let expected = match migration.checksum() {
+                Some(checksum) => checksum,
+                None => continue,
+            };
 
-        let mut statement = conn.prepare(q)?;
+            let stored = match self.version_store.checksum(&conn, migration.version())? {
+                Some(checksum) => checksum,
+                None => continue,
+            };
 
-        let result = statement.query_map([], block)?;
+            if stored != expected {
+                drifted.push(migration.version());
+            }
+        }
 
-        result.collect()
+        if drifted.is_empty() {
+            Ok(())
+        } else {
+            Err(SqliteMigrationError::ValidationError(drifted))
+        }
     }
 }
 
@@ -139,50 +244,59 @@ impl Adapter for SqliteAdapter {
 
     type Error = SqliteMigrationError;
 
-    /// Panics if `setup_schema` hasn't previously been called or if the query otherwise fails.
+    /// Returns [`SqliteMigrationError::SchemaNotInitialized`] if `setup_schema` hasn't
+    /// previously been called, or another error if the query otherwise fails.
     fn current_version(&self) -> Result<Option<Version>> {
-        let query = "SELECT version FROM schemamama ORDER BY version DESC LIMIT 1;";
-
-        match self.query_row(query, |row| row.get(0)) {
-            Ok(version) => Ok(Some(version)),
-            Err(SqliteError::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(e.into()),
-        }
+        self.version_store
+            .current_version(&self.connection.borrow())
     }
 
-    /// Panics if `setup_schema` hasn't previously been called or if the query otherwise fails.
+    /// Returns [`SqliteMigrationError::SchemaNotInitialized`] if `setup_schema` hasn't
+    /// previously been called, or another error if the query otherwise fails.
     fn migrated_versions(&self) -> Result<BTreeSet<Version>> {
-        let query = "SELECT version FROM schemamama;";
-
-        let rows = self.query_map(query, |row_result| row_result.get::<usize, i64>(0))?;
-
-        let mut versions = BTreeSet::new();
-
-        for vresult in rows {
-            versions.insert(vresult);
-        }
-
-        Ok(versions)
+        self.version_store
+            .migrated_versions(&self.connection.borrow())
     }
 
-    /// Panics if `setup_schema` hasn't previously been called or if the migration otherwise fails.
+    /// Returns [`SqliteMigrationError::SchemaNotInitialized`] if `setup_schema` hasn't
+    /// previously been called, or another error if the migration otherwise fails.
+    ///
+    /// If this adapter was created with [`SqliteAdapter::with_backup`], a backup is taken before
+    /// the migration runs, and its path is attached to any returned error via
+    /// [`SqliteMigrationError::FailedAfterBackup`].
     fn apply_migration(&self, migration: &dyn SqliteMigration) -> Result<()> {
+        let backup_path = self.maybe_backup()?;
+
         self.execute_transaction(|transaction| {
             migration.up(&transaction)?;
-            self.record_version(transaction, migration.version())?;
+            self.version_store.record_version(
+                transaction,
+                migration.version(),
+                migration.checksum(),
+            )?;
             Ok(())
-        })?;
+        })
+        .map_err(|e| self.attach_backup_path(e, &backup_path))?;
 
         Ok(())
     }
 
-    /// Panics if `setup_schema` hasn't previously been called or if the migration otherwise fails.
+    /// Returns [`SqliteMigrationError::SchemaNotInitialized`] if `setup_schema` hasn't
+    /// previously been called, or another error if the migration otherwise fails.
+    ///
+    /// If this adapter was created with [`SqliteAdapter::with_backup`], a backup is taken before
+    /// the migration runs, and its path is attached to any returned error via
+    /// [`SqliteMigrationError::FailedAfterBackup`].
     fn revert_migration(&self, migration: &dyn SqliteMigration) -> Result<()> {
+        let backup_path = self.maybe_backup()?;
+
         self.execute_transaction(|transaction| {
             migration.down(&transaction)?;
-            self.erase_version(transaction, migration.version())?;
+            self.version_store
+                .erase_version(transaction, migration.version())?;
             Ok(())
-        })?;
+        })
+        .map_err(|e| self.attach_backup_path(e, &backup_path))?;
 
         Ok(())
     }
@@ -190,11 +304,15 @@ impl Adapter for SqliteAdapter {
 
 #[cfg(test)]
 mod tests {
-    use super::{SqliteAdapter, SqliteMigration};
+    use super::{
+        ConnectionInitializer, SqlMigration, SqliteAdapter, SqliteMigration, SqliteMigrationError,
+        UserVersionStore,
+    };
 
     use rusqlite::{Connection as SqliteConnection, Result as SqliteResult};
-    use schemamama::{migration, Migrator};
+    use schemamama::{migration, Adapter, Migrator};
     use std::cell::RefCell;
+    use std::collections::BTreeSet;
     use std::rc::Rc;
 
     struct CreateUsers;
@@ -215,9 +333,9 @@ mod tests {
     pub fn test_register() {
         let conn = Rc::new(RefCell::new(SqliteConnection::open_in_memory().unwrap()));
 
-        let adapter = SqliteAdapter::new(conn);
+        let adapter = SqliteAdapter::new(conn, None, None).unwrap();
 
-        adapter.setup_schema();
+        adapter.setup_schema().unwrap();
 
         let mut migrator = Migrator::new(adapter);
 
@@ -231,4 +349,186 @@ mod tests {
 
         assert_eq!(migrator.current_version().unwrap(), None);
     }
+
+    struct RecordingInitializer {
+        finished: Rc<RefCell<bool>>,
+    }
+
+    impl ConnectionInitializer for RecordingInitializer {
+        fn finish(&self, _conn: &SqliteConnection) -> SqliteResult<()> {
+            *self.finished.borrow_mut() = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_connection_initializer_finish() {
+        let conn = Rc::new(RefCell::new(SqliteConnection::open_in_memory().unwrap()));
+        let finished = Rc::new(RefCell::new(false));
+
+        let initializer = RecordingInitializer {
+            finished: finished.clone(),
+        };
+
+        let adapter = SqliteAdapter::new(conn, Some(Box::new(initializer)), None).unwrap();
+
+        adapter.setup_schema().unwrap();
+
+        // schemamama::Migrator takes ownership of the adapter and never hands it back, so
+        // finish() is only reachable by driving migrations through the Adapter impl directly.
+        adapter.apply_migration(&CreateUsers).unwrap();
+        adapter.revert_migration(&CreateUsers).unwrap();
+
+        assert!(!*finished.borrow());
+
+        adapter.finish().unwrap();
+
+        assert!(*finished.borrow());
+    }
+
+    #[test]
+    pub fn test_sql_migration() {
+        let conn = Rc::new(RefCell::new(SqliteConnection::open_in_memory().unwrap()));
+
+        let adapter = SqliteAdapter::new(conn, None, None).unwrap();
+
+        adapter.setup_schema().unwrap();
+
+        let mut migrator = Migrator::new(adapter);
+
+        migrator.register(Box::new(SqlMigration::new(
+            1,
+            "create posts table",
+            "CREATE TABLE posts (id BIGINT PRIMARY KEY); CREATE INDEX posts_id ON posts (id);",
+            Some("DROP TABLE posts;"),
+        )));
+
+        migrator.up(Some(1)).unwrap();
+
+        assert_eq!(migrator.current_version().unwrap(), Some(1));
+
+        migrator.down(None).unwrap();
+
+        assert_eq!(migrator.current_version().unwrap(), None);
+    }
+
+    #[test]
+    pub fn test_validate_detects_checksum_drift() {
+        let conn = Rc::new(RefCell::new(SqliteConnection::open_in_memory().unwrap()));
+
+        let adapter = SqliteAdapter::new(conn, None, None).unwrap();
+
+        adapter.setup_schema().unwrap();
+
+        let applied = SqlMigration::new(
+            1,
+            "create posts table",
+            "CREATE TABLE posts (id BIGINT PRIMARY KEY);",
+            None,
+        );
+
+        adapter.apply_migration(&applied).unwrap();
+
+        assert!(adapter.validate(&[&applied]).is_ok());
+
+        let changed = SqlMigration::new(
+            1,
+            "create posts table",
+            "CREATE TABLE posts (id BIGINT PRIMARY KEY, title TEXT);",
+            None,
+        );
+
+        match adapter.validate(&[&changed]) {
+            Err(SqliteMigrationError::ValidationError(versions)) => {
+                assert_eq!(versions, vec![1]);
+            }
+            other => panic!("expected ValidationError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_backup_before_migration() {
+        let dir = std::env::temp_dir();
+        let conn = Rc::new(RefCell::new(SqliteConnection::open_in_memory().unwrap()));
+
+        let adapter = SqliteAdapter::with_backup(conn, None, None, dir.clone()).unwrap();
+
+        adapter.setup_schema().unwrap();
+
+        let migration = CreateUsers;
+
+        adapter.apply_migration(&migration).unwrap();
+
+        let backups: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("schemamama-")
+            })
+            .collect();
+
+        assert!(!backups.is_empty());
+
+        for backup in backups {
+            let _ = std::fs::remove_file(backup.path());
+        }
+    }
+
+    #[test]
+    pub fn test_user_version_store() {
+        let conn = Rc::new(RefCell::new(SqliteConnection::open_in_memory().unwrap()));
+
+        let version_store = UserVersionStore::new(BTreeSet::from([1, 2])).unwrap();
+
+        let adapter = SqliteAdapter::new(conn, None, Some(Box::new(version_store))).unwrap();
+
+        adapter.setup_schema().unwrap();
+
+        let mut migrator = Migrator::new(adapter);
+
+        migrator.register(Box::new(CreateUsers));
+        migrator.register(Box::new(SqlMigration::new(
+            2,
+            "add email to users",
+            "ALTER TABLE users ADD COLUMN email TEXT;",
+            None,
+        )));
+
+        migrator.up(None).unwrap();
+
+        assert_eq!(migrator.current_version().unwrap(), Some(2));
+    }
+
+    #[test]
+    pub fn test_user_version_store_rejects_non_contiguous_versions() {
+        match UserVersionStore::new(BTreeSet::from([1, 3])) {
+            Err(SqliteMigrationError::NonContiguousVersions(1, 3)) => {}
+            other => panic!("expected NonContiguousVersions, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_user_version_store_rejects_versions_too_wide_for_user_version() {
+        let version = i64::from(i32::MAX) + 1;
+
+        match UserVersionStore::new(BTreeSet::from([version])) {
+            Err(SqliteMigrationError::VersionOutOfRange(v)) if v == version => {}
+            other => panic!("expected VersionOutOfRange, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn test_current_version_before_setup_schema_returns_error() {
+        let conn = Rc::new(RefCell::new(SqliteConnection::open_in_memory().unwrap()));
+
+        let adapter = SqliteAdapter::new(conn, None, None).unwrap();
+
+        match adapter.current_version() {
+            Err(SqliteMigrationError::SchemaNotInitialized) => {}
+            other => panic!("expected SchemaNotInitialized, got {:?}", other),
+        }
+    }
 }
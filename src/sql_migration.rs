@@ -0,0 +1,87 @@
+use crate::SqliteMigration;
+use rusqlite::{Connection as SqliteConnection, Result as SqliteResult};
+use schemamama::{Migration, Version};
+use sha2::{Digest, Sha256};
+
+/// A migration whose `up`/`down` bodies are plain SQL text rather than Rust code.
+///
+/// The SQL is run through [`Connection::execute_batch`](rusqlite::Connection::execute_batch),
+/// so a single `up_sql`/`down_sql` string may contain several statements (e.g. a `CREATE TABLE`
+/// followed by `CREATE INDEX`). This makes it possible to declare a whole migration set as data
+/// — for example with `include_str!` — instead of writing a `SqliteMigration` impl per version.
+pub struct SqlMigration {
+    version: Version,
+    description: String,
+    up_sql: String,
+    down_sql: Option<String>,
+}
+
+impl SqlMigration {
+    /// Create a migration from SQL text already held in memory, such as the output of
+    /// `include_str!`.
+    pub fn new<D: Into<String>, S: Into<String>>(
+        version: Version,
+        description: D,
+        up_sql: S,
+        down_sql: Option<S>,
+    ) -> SqlMigration {
+        SqlMigration {
+            version,
+            description: description.into(),
+            up_sql: up_sql.into(),
+            down_sql: down_sql.map(Into::into),
+        }
+    }
+
+    /// Create a migration by reading its `up`/`down` SQL from files at call time.
+    ///
+    /// For SQL baked into the binary at compile time, prefer `include_str!` with
+    /// [`SqlMigration::new`] instead.
+    pub fn from_files<D: Into<String>, P: AsRef<std::path::Path>>(
+        version: Version,
+        description: D,
+        up_path: P,
+        down_path: Option<P>,
+    ) -> std::io::Result<SqlMigration> {
+        let up_sql = std::fs::read_to_string(up_path)?;
+        let down_sql = down_path.map(std::fs::read_to_string).transpose()?;
+
+        Ok(SqlMigration {
+            version,
+            description: description.into(),
+            up_sql,
+            down_sql,
+        })
+    }
+}
+
+impl Migration for SqlMigration {
+    fn version(&self) -> Version {
+        self.version
+    }
+
+    fn description(&self) -> String {
+        self.description.clone()
+    }
+}
+
+impl SqliteMigration for SqlMigration {
+    fn up(&self, conn: &SqliteConnection) -> SqliteResult<()> {
+        conn.execute_batch(&self.up_sql)
+    }
+
+    fn down(&self, conn: &SqliteConnection) -> SqliteResult<()> {
+        match &self.down_sql {
+            Some(sql) => conn.execute_batch(sql),
+            None => Ok(()),
+        }
+    }
+
+    fn checksum(&self) -> Option<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.up_sql.as_bytes());
+        hasher.update([0]);
+        hasher.update(self.down_sql.as_deref().unwrap_or("").as_bytes());
+        Some(format!("{:x}", hasher.finalize()))
+    }
+}
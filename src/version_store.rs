@@ -0,0 +1,245 @@
+use crate::{Result, SqliteMigrationError};
+#[allow(unused_imports)]
+use log::warn;
+use rusqlite::{Connection as SqliteConnection, Error as SqliteError, Result as SqliteResult};
+use schemamama::Version;
+use std::collections::BTreeSet;
+
+/// Where an adapter records which migrations have been applied.
+///
+/// The default [`TableVersionStore`] keeps a dedicated `schemamama` bookkeeping table, including
+/// a checksum per version for
+/// [`SqliteAdapter::validate`](crate::SqliteAdapter::validate). [`UserVersionStore`] instead
+/// piggybacks on SQLite's own `PRAGMA user_version`, for callers that don't want the extra
+/// table.
+pub trait VersionStore {
+    /// Create whatever table/schema this store needs. Called once via
+    /// [`SqliteAdapter::setup_schema`](crate::SqliteAdapter::setup_schema).
+    fn setup(&self, conn: &SqliteConnection) -> Result<()>;
+
+    fn current_version(&self, conn: &SqliteConnection) -> Result<Option<Version>>;
+
+    fn migrated_versions(&self, conn: &SqliteConnection) -> Result<BTreeSet<Version>>;
+
+    fn record_version(
+        &self,
+        conn: &SqliteConnection,
+        version: Version,
+        checksum: Option<String>,
+    ) -> SqliteResult<()>;
+
+    fn erase_version(&self, conn: &SqliteConnection, version: Version) -> SqliteResult<()>;
+
+    /// The checksum recorded for `version`, if this store tracks checksums at all.
+    fn checksum(&self, conn: &SqliteConnection, version: Version) -> Result<Option<String>>;
+}
+
+/// The original version store: a dedicated `schemamama` table holding one row per applied
+/// migration, plus a `checksum` column.
+pub struct TableVersionStore;
+
+impl VersionStore for TableVersionStore {
+    fn setup(&self, conn: &SqliteConnection) -> Result<()> {
+        let query =
+            "CREATE TABLE IF NOT EXISTS schemamama (version BIGINT PRIMARY KEY, checksum TEXT);";
+        conn.execute(query, [])?;
+
+        let has_checksum_column = conn
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('schemamama') WHERE name = 'checksum';",
+                [],
+                |row| row.get::<usize, i64>(0),
+            )
+            .map(|count| count > 0)?;
+
+        if !has_checksum_column {
+            conn.execute("ALTER TABLE schemamama ADD COLUMN checksum TEXT;", [])?;
+        }
+
+        Ok(())
+    }
+
+    fn current_version(&self, conn: &SqliteConnection) -> Result<Option<Version>> {
+        let query = "SELECT version FROM schemamama ORDER BY version DESC LIMIT 1;";
+
+        match conn.query_row(query, [], |row| row.get(0)) {
+            Ok(version) => Ok(Some(version)),
+            Err(SqliteError::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(map_missing_table(e)),
+        }
+    }
+
+    fn migrated_versions(&self, conn: &SqliteConnection) -> Result<BTreeSet<Version>> {
+        let query = "SELECT version FROM schemamama;";
+
+        let mut stmt = conn.prepare(query).map_err(map_missing_table)?;
+        let rows = stmt.query_map([], |row| row.get::<usize, i64>(0))?;
+
+        let mut versions = BTreeSet::new();
+        for vresult in rows {
+            versions.insert(vresult?);
+        }
+
+        Ok(versions)
+    }
+
+    // Fails if `setup` hasn't previously been called or if the insertion query otherwise fails.
+    fn record_version(
+        &self,
+        conn: &SqliteConnection,
+        version: Version,
+        checksum: Option<String>,
+    ) -> SqliteResult<()> {
+        let query = "INSERT INTO schemamama (version, checksum) VALUES ($1, $2);";
+        let mut stmt = conn.prepare(query)?;
+
+        match stmt.execute(rusqlite::params![version, checksum]) {
+            Err(e) => {
+                warn!("Failed to insert version {:?}: {:?}", version, e);
+                Err(e)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Fails if `setup` hasn't previously been called or if the deletion query otherwise fails.
+    fn erase_version(&self, conn: &SqliteConnection, version: Version) -> SqliteResult<()> {
+        let query = "DELETE FROM schemamama WHERE version = $1;";
+        let mut stmt = conn.prepare(query)?;
+
+        match stmt.execute([&version]) {
+            Err(e) => {
+                warn!("Failed to delete version {:?}: {:?}", version, e);
+                Err(e)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn checksum(&self, conn: &SqliteConnection, version: Version) -> Result<Option<String>> {
+        match conn.query_row(
+            "SELECT checksum FROM schemamama WHERE version = $1;",
+            [&version],
+            |row| row.get(0),
+        ) {
+            Ok(checksum) => Ok(checksum),
+            Err(SqliteError::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(map_missing_table(e)),
+        }
+    }
+}
+
+/// Recognise SQLite's "no such table" failure for the `schemamama` table and turn it into
+/// [`SqliteMigrationError::SchemaNotInitialized`], so callers can tell "the schema table isn't
+/// set up" apart from other SQLite failures.
+pub(crate) fn map_missing_table(error: SqliteError) -> SqliteMigrationError {
+    match &error {
+        SqliteError::SqliteFailure(_, Some(message))
+            if message.contains("no such table: schemamama") =>
+        {
+            SqliteMigrationError::SchemaNotInitialized
+        }
+        _ => SqliteMigrationError::from(error),
+    }
+}
+
+/// A version store that uses SQLite's own `PRAGMA user_version` instead of a bookkeeping table.
+///
+/// `PRAGMA user_version` only holds a single integer, so this store models linear migrations:
+/// any registered version less than or equal to the current `user_version` is considered
+/// applied, regardless of whether it was actually run through this crate. A `user_version` of
+/// `0` means no version is applied, so registered versions of `0` or below never count as
+/// applied. Because of that, the
+/// set of registered version numbers must be contiguous, or [`UserVersionStore::new`] returns
+/// [`SqliteMigrationError::NonContiguousVersions`]. It never tracks checksums, since there's
+/// nowhere to store one per version.
+///
+/// `PRAGMA user_version` is stored as a 32-bit signed integer, not schemamama's `i64`, so
+/// [`UserVersionStore::new`] also rejects any version outside `i32`'s range (timestamp-style
+/// versions like `20230101000000` don't fit) with [`SqliteMigrationError::VersionOutOfRange`]
+/// rather than silently truncating it on write.
+#[derive(Debug)]
+pub struct UserVersionStore {
+    versions: Vec<Version>,
+}
+
+impl UserVersionStore {
+    /// Build a store for `versions`, which must be contiguous (`v`, `v + 1`, `v + 2`, ...) and
+    /// fit within `i32`, the width of `PRAGMA user_version`.
+    pub fn new(versions: BTreeSet<Version>) -> Result<UserVersionStore> {
+        let versions: Vec<Version> = versions.into_iter().collect();
+
+        for version in &versions {
+            if Version::from(i32::MIN) > *version || *version > Version::from(i32::MAX) {
+                return Err(SqliteMigrationError::VersionOutOfRange(*version));
+            }
+        }
+
+        for pair in versions.windows(2) {
+            if pair[1] != pair[0] + 1 {
+                return Err(SqliteMigrationError::NonContiguousVersions(
+                    pair[0], pair[1],
+                ));
+            }
+        }
+
+        Ok(UserVersionStore { versions })
+    }
+}
+
+impl VersionStore for UserVersionStore {
+    fn setup(&self, _conn: &SqliteConnection) -> Result<()> {
+        Ok(())
+    }
+
+    fn current_version(&self, conn: &SqliteConnection) -> Result<Option<Version>> {
+        let version: Version = conn.query_row("PRAGMA user_version;", [], |row| row.get(0))?;
+
+        if version == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(version))
+        }
+    }
+
+    fn migrated_versions(&self, conn: &SqliteConnection) -> Result<BTreeSet<Version>> {
+        let current = self.current_version(conn)?;
+
+        Ok(match current {
+            Some(current) => self
+                .versions
+                .iter()
+                .copied()
+                .filter(|version| *version <= current)
+                .collect(),
+            None => BTreeSet::new(),
+        })
+    }
+
+    fn record_version(
+        &self,
+        conn: &SqliteConnection,
+        version: Version,
+        _checksum: Option<String>,
+    ) -> SqliteResult<()> {
+        conn.execute(&format!("PRAGMA user_version = {};", version), [])
+            .map(|_| ())
+    }
+
+    fn erase_version(&self, conn: &SqliteConnection, version: Version) -> SqliteResult<()> {
+        let previous = self
+            .versions
+            .iter()
+            .copied()
+            .filter(|v| *v < version)
+            .max()
+            .unwrap_or(0);
+
+        conn.execute(&format!("PRAGMA user_version = {};", previous), [])
+            .map(|_| ())
+    }
+
+    fn checksum(&self, _conn: &SqliteConnection, _version: Version) -> Result<Option<String>> {
+        Ok(None)
+    }
+}